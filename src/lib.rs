@@ -14,12 +14,75 @@
 use bevy_app::prelude::*;
 use bevy_ecs::prelude::*;
 use bevy_log::prelude::*;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 /// Default WebSocket path for the relay endpoint.
 pub const DEFAULT_RELAY_PATH: &str = "brp-relay";
 
+/// WebSocket close code used to reject an unauthenticated connection.
+///
+/// `1008` (policy violation) lets the browser client distinguish an auth
+/// rejection from an ordinary transport drop and stop retrying.
+pub const AUTH_REJECTED_CLOSE_CODE: u16 = 1008;
+
+/// Authentication state of the relay connection, tracked on [`BrpRelayStatus`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum AuthState {
+    /// No auth token is configured; the connection is open by design.
+    #[default]
+    Disabled,
+    /// A token is configured but no connection has been established yet.
+    Pending,
+    /// A token is configured and the relay accepted the connection.
+    Authenticated,
+    /// The relay rejected the connection's token.
+    Rejected,
+}
+
+impl AuthState {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => Self::Pending,
+            2 => Self::Authenticated,
+            3 => Self::Rejected,
+            _ => Self::Disabled,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Self::Disabled => 0,
+            Self::Pending => 1,
+            Self::Authenticated => 2,
+            Self::Rejected => 3,
+        }
+    }
+}
+
+/// Exponential backoff schedule used by the reconnection subsystem.
+///
+/// Reconnect attempts start at [`Self::initial`] and double after each failed
+/// attempt, clamped to [`Self::max`]. The schedule resets once a connection
+/// opens successfully.
+#[derive(Clone, Copy)]
+pub struct BackoffConfig {
+    /// Delay before the first reconnect attempt.
+    pub initial: Duration,
+    /// Upper bound the delay is clamped to as it doubles.
+    pub max: Duration,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(250),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
 /// Resource that tracks the WebSocket relay connection status.
 ///
 /// Inserted by [`BrpWebSocketRelayPlugin`] and updated automatically
@@ -27,12 +90,14 @@ pub const DEFAULT_RELAY_PATH: &str = "brp-relay";
 #[derive(Resource, Clone)]
 pub struct BrpRelayStatus {
     connected: Arc<AtomicBool>,
+    auth: Arc<AtomicU8>,
 }
 
 impl Default for BrpRelayStatus {
     fn default() -> Self {
         Self {
             connected: Arc::new(AtomicBool::new(false)),
+            auth: Arc::new(AtomicU8::new(AuthState::Disabled.as_u8())),
         }
     }
 }
@@ -42,6 +107,11 @@ impl BrpRelayStatus {
     pub fn connected(&self) -> bool {
         self.connected.load(Ordering::Relaxed)
     }
+
+    /// Returns the current authentication state of the connection.
+    pub fn auth_state(&self) -> AuthState {
+        AuthState::from_u8(self.auth.load(Ordering::Relaxed))
+    }
 }
 
 /// Plugin that connects to a WebSocket relay server and bridges BRP requests
@@ -58,6 +128,24 @@ pub struct BrpWebSocketRelayPlugin {
     ///
     /// Ignored when [`Self::url`] is set. Defaults to [`DEFAULT_RELAY_PATH`].
     pub path: String,
+    /// Maximum number of reconnect attempts after a dropped connection.
+    ///
+    /// `None` (the default) retries indefinitely.
+    pub max_retries: Option<u32>,
+    /// Exponential backoff schedule for reconnect attempts.
+    pub backoff: BackoffConfig,
+    /// Optional token gating the relay connection.
+    ///
+    /// When set, it is sent as the WebSocket subprotocol during the handshake
+    /// so the relay can validate it before accepting the connection. The relay
+    /// rejects a missing or mismatched token with [`AUTH_REJECTED_CLOSE_CODE`].
+    pub auth_token: Option<String>,
+    /// Extra query parameters appended to the connection URL.
+    ///
+    /// Appended to the auto-detected URL and to [`Self::url`] when it is set.
+    /// Useful for origin/key gating schemes that read the query string rather
+    /// than the subprotocol.
+    pub query: Vec<(String, String)>,
 }
 
 impl Default for BrpWebSocketRelayPlugin {
@@ -65,6 +153,10 @@ impl Default for BrpWebSocketRelayPlugin {
         Self {
             url: None,
             path: DEFAULT_RELAY_PATH.to_string(),
+            max_retries: None,
+            backoff: BackoffConfig::default(),
+            auth_token: None,
+            query: Vec::new(),
         }
     }
 }
@@ -78,6 +170,11 @@ impl Plugin for BrpWebSocketRelayPlugin {
             app.insert_resource(wasm::RelayConfig {
                 url: self.url.clone(),
                 path: self.path.clone(),
+                backoff_initial_ms: self.backoff.initial.as_millis() as u32,
+                backoff_max_ms: self.backoff.max.as_millis() as u32,
+                max_retries: self.max_retries,
+                auth_token: self.auth_token.clone(),
+                query: self.query.clone(),
             });
             app.add_systems(Startup, wasm::start_websocket_relay);
         }
@@ -90,12 +187,23 @@ impl Plugin for BrpWebSocketRelayPlugin {
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::BrpRelayServerPlugin;
+
 #[cfg(target_arch = "wasm32")]
 mod wasm {
     use bevy_ecs::prelude::*;
     use bevy_log::prelude::*;
     use bevy_remote::{BrpError, BrpMessage, BrpSender};
+    use futures::future::{join_all, select, Either};
+    use futures::pin_mut;
+    use gloo_timers::callback::Timeout;
     use serde_json::Value;
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
     use wasm_bindgen::prelude::*;
     use wasm_bindgen::JsCast;
     use web_sys::WebSocket;
@@ -104,6 +212,69 @@ mod wasm {
     pub(crate) struct RelayConfig {
         pub url: Option<String>,
         pub path: String,
+        pub backoff_initial_ms: u32,
+        pub backoff_max_ms: u32,
+        pub max_retries: Option<u32>,
+        pub auth_token: Option<String>,
+        pub query: Vec<(String, String)>,
+    }
+
+    /// A `+watch` subscription that must survive across reconnects.
+    ///
+    /// The relay re-sends these on a fresh socket after a successful reconnect
+    /// so subscribers keep receiving updates without client involvement.
+    #[derive(Clone)]
+    struct WatchEntry {
+        id: Value,
+        method: String,
+        params: Option<Value>,
+    }
+
+    /// Shared reconnect state, patterned on the ethers-rs "RRR" (Reconnection &
+    /// Request Reissuance) WebSocket transport.
+    ///
+    /// Holds everything needed to rebuild the socket after a drop: the resolved
+    /// URL, the [`BrpSender`] channel, the backoff schedule, and the registry of
+    /// in-flight watch subscriptions.
+    struct RelayContext {
+        url: String,
+        /// Token offered as the WebSocket subprotocol, if auth is configured.
+        auth_token: Option<String>,
+        sender: async_channel::Sender<BrpMessage>,
+        status: Arc<AtomicBool>,
+        auth: Arc<std::sync::atomic::AtomicU8>,
+        backoff_initial_ms: u32,
+        backoff_max_ms: u32,
+        max_retries: Option<u32>,
+        retries: Cell<u32>,
+        current_backoff_ms: Cell<u32>,
+        scheduled: Cell<bool>,
+        /// Set once the connection reaches a terminal state (e.g. auth
+        /// rejection); suppresses any further reconnect, including one already
+        /// armed by an earlier `onerror`.
+        terminal: Cell<bool>,
+        watches: RefCell<HashMap<String, WatchEntry>>,
+        /// Cancel signal for each active watch stream, keyed like [`watches`].
+        cancels: RefCell<HashMap<String, async_channel::Sender<()>>>,
+    }
+
+    /// Registry key for a watch subscription.
+    ///
+    /// `serde_json::Value` is not `Hash`, so the JSON-RPC `id` is keyed by its
+    /// canonical string form while the original value is kept in [`WatchEntry`].
+    fn watch_key(id: &Value) -> String {
+        id.to_string()
+    }
+
+    /// Append `key=value` pairs to a URL, choosing `?` or `&` as appropriate.
+    fn append_query(mut url: String, params: &[(String, String)]) -> String {
+        for (key, value) in params {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str(key);
+            url.push('=');
+            url.push_str(value);
+        }
+        url
     }
 
     pub(crate) fn start_websocket_relay(
@@ -111,7 +282,7 @@ mod wasm {
         config: Res<RelayConfig>,
         status: Res<super::BrpRelayStatus>,
     ) {
-        let url = config.url.clone().unwrap_or_else(|| {
+        let mut url = config.url.clone().unwrap_or_else(|| {
             let window = web_sys::window().expect("no global window");
             let location = window.location();
             let host = location.host().expect("no host in location");
@@ -120,70 +291,204 @@ mod wasm {
             } else {
                 "ws:"
             };
-            format!("{protocol}//{host}/{}", config.path)
+            let base = format!("{protocol}//{host}/{}", config.path);
+            append_query(base, &config.query)
         });
+        // Auto-detected URLs get the extra query params above; an explicit URL
+        // is taken verbatim except for appending any configured params.
+        if config.url.is_some() && !config.query.is_empty() {
+            url = append_query(url, &config.query);
+        }
 
-        info!("BRP WebSocket relay: connecting to {url}");
+        // A configured token moves the status to `Pending` until the relay
+        // confirms the connection; without one, auth stays `Disabled`.
+        let auth_state = if config.auth_token.is_some() {
+            super::AuthState::Pending
+        } else {
+            super::AuthState::Disabled
+        };
+        status
+            .auth
+            .store(auth_state.as_u8(), Ordering::Relaxed);
 
-        let ws = WebSocket::new(&url).expect("failed to create WebSocket");
+        let ctx = Rc::new(RelayContext {
+            url,
+            auth_token: config.auth_token.clone(),
+            sender: (*brp_sender).clone(),
+            status: status.connected.clone(),
+            auth: status.auth.clone(),
+            backoff_initial_ms: config.backoff_initial_ms,
+            backoff_max_ms: config.backoff_max_ms,
+            max_retries: config.max_retries,
+            retries: Cell::new(0),
+            current_backoff_ms: Cell::new(config.backoff_initial_ms),
+            scheduled: Cell::new(false),
+            terminal: Cell::new(false),
+            watches: RefCell::new(HashMap::new()),
+            cancels: RefCell::new(HashMap::new()),
+        });
+
+        connect(ctx);
+    }
+
+    /// Open a WebSocket and wire up its event handlers.
+    ///
+    /// Re-invoked by [`schedule_reconnect`] after a drop; all connection state
+    /// lives in the shared [`RelayContext`] so each socket is disposable.
+    fn connect(ctx: Rc<RelayContext>) {
+        ctx.scheduled.set(false);
+
+        // A reconnect armed before the connection turned terminal (e.g. an
+        // `onerror` that preceded an auth-rejection `onclose`) must not fire.
+        if ctx.terminal.get() {
+            return;
+        }
+
+        info!("BRP WebSocket relay: connecting to {}", ctx.url);
+
+        // When a token is configured it is offered as the WebSocket subprotocol
+        // so the relay can gate the connection during the handshake.
+        let ws = match &ctx.auth_token {
+            Some(token) => WebSocket::new_with_str(&ctx.url, token),
+            None => WebSocket::new(&ctx.url),
+        }
+        .expect("failed to create WebSocket");
 
         // Text mode for JSON-RPC messages
         ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
 
-        let sender: async_channel::Sender<BrpMessage> = (*brp_sender).clone();
-        let ws_for_msg = ws.clone();
-
         // Handle incoming JSON-RPC requests from the relay
-        let onmessage = Closure::<dyn FnMut(_)>::new(move |event: web_sys::MessageEvent| {
-            let data = event.data();
-            let Some(text) = data.dyn_ref::<js_sys::JsString>() else {
-                return;
-            };
-            let text: String = text.into();
-            let sender = sender.clone();
-            let ws = ws_for_msg.clone();
+        let onmessage = {
+            let ctx = ctx.clone();
+            let ws = ws.clone();
+            Closure::<dyn FnMut(_)>::new(move |event: web_sys::MessageEvent| {
+                let data = event.data();
+                let Some(text) = data.dyn_ref::<js_sys::JsString>() else {
+                    return;
+                };
+                let text: String = text.into();
+                let ctx = ctx.clone();
+                let ws = ws.clone();
 
-            wasm_bindgen_futures::spawn_local(async move {
-                process_request(text, sender, ws).await;
-            });
-        });
+                wasm_bindgen_futures::spawn_local(async move {
+                    process_request(text, ctx, ws).await;
+                });
+            })
+        };
         ws.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
         onmessage.forget();
 
-        let status_connected = status.connected.clone();
-        let status_disconnected = status.connected.clone();
+        let onopen = {
+            let ctx = ctx.clone();
+            let ws = ws.clone();
+            Closure::<dyn FnMut()>::new(move || {
+                info!("BRP WebSocket relay: connected");
+                ctx.status.store(true, Ordering::Relaxed);
+                if ctx.auth_token.is_some() {
+                    ctx.auth
+                        .store(super::AuthState::Authenticated.as_u8(), Ordering::Relaxed);
+                }
+                // Successful connection resets the backoff schedule.
+                ctx.retries.set(0);
+                ctx.current_backoff_ms.set(ctx.backoff_initial_ms);
 
-        let onopen = Closure::<dyn FnMut()>::new(move || {
-            info!("BRP WebSocket relay: connected");
-            status_connected.store(true, std::sync::atomic::Ordering::Relaxed);
-        });
+                // Re-issue every registered watch on the fresh socket.
+                let entries: Vec<WatchEntry> =
+                    ctx.watches.borrow().values().cloned().collect();
+                for entry in entries {
+                    info!(
+                        "BRP WebSocket relay: re-issuing watch {}",
+                        watch_key(&entry.id)
+                    );
+                    let ctx = ctx.clone();
+                    let ws = ws.clone();
+                    wasm_bindgen_futures::spawn_local(async move {
+                        dispatch(ctx, ws, Some(entry.id), entry.method, entry.params).await;
+                    });
+                }
+            })
+        };
         ws.set_onopen(Some(onopen.as_ref().unchecked_ref()));
         onopen.forget();
 
-        let onclose = Closure::<dyn FnMut(_)>::new(move |event: web_sys::CloseEvent| {
-            warn!(
-                "BRP WebSocket relay: disconnected (code={}, reason={})",
-                event.code(),
-                event.reason()
-            );
-            status_disconnected.store(false, std::sync::atomic::Ordering::Relaxed);
-        });
+        let onclose = {
+            let ctx = ctx.clone();
+            Closure::<dyn FnMut(_)>::new(move |event: web_sys::CloseEvent| {
+                warn!(
+                    "BRP WebSocket relay: disconnected (code={}, reason={})",
+                    event.code(),
+                    event.reason()
+                );
+                ctx.status.store(false, Ordering::Relaxed);
+                // Tear down every watch task tied to the dead socket so none
+                // linger; the registry in `watches` is preserved for re-issue.
+                for (_, cancel) in ctx.cancels.borrow_mut().drain() {
+                    let _ = cancel.try_send(());
+                }
+                // An auth rejection is terminal: mark the state and do not retry,
+                // since the token will not change on its own.
+                if event.code() == super::AUTH_REJECTED_CLOSE_CODE {
+                    error!("BRP WebSocket relay: connection rejected (unauthorized)");
+                    ctx.auth
+                        .store(super::AuthState::Rejected.as_u8(), Ordering::Relaxed);
+                    // Mark terminal so any reconnect already armed by an earlier
+                    // `onerror` no-ops when it fires.
+                    ctx.terminal.set(true);
+                    return;
+                }
+                schedule_reconnect(ctx.clone());
+            })
+        };
         ws.set_onclose(Some(onclose.as_ref().unchecked_ref()));
         onclose.forget();
 
-        let onerror = Closure::<dyn FnMut(_)>::new(|_: web_sys::ErrorEvent| {
-            error!("BRP WebSocket relay: connection error");
-        });
+        let onerror = {
+            let ctx = ctx.clone();
+            Closure::<dyn FnMut(_)>::new(move |_: web_sys::ErrorEvent| {
+                error!("BRP WebSocket relay: connection error");
+                // Mirror `onclose` cleanup: an error may fire without a close.
+                ctx.status.store(false, Ordering::Relaxed);
+                for (_, cancel) in ctx.cancels.borrow_mut().drain() {
+                    let _ = cancel.try_send(());
+                }
+                schedule_reconnect(ctx.clone());
+            })
+        };
         ws.set_onerror(Some(onerror.as_ref().unchecked_ref()));
         onerror.forget();
     }
 
-    /// Process a single JSON-RPC request from the relay.
-    async fn process_request(
-        text: String,
-        sender: async_channel::Sender<BrpMessage>,
-        ws: WebSocket,
-    ) {
+    /// Schedule a reconnect after the current backoff delay, doubling the delay
+    /// up to the configured cap. A no-op if a reconnect is already pending or
+    /// the retry budget is exhausted.
+    fn schedule_reconnect(ctx: Rc<RelayContext>) {
+        if ctx.scheduled.get() || ctx.terminal.get() {
+            return;
+        }
+        if let Some(max) = ctx.max_retries {
+            if ctx.retries.get() >= max {
+                error!("BRP WebSocket relay: giving up after {max} reconnect attempts");
+                return;
+            }
+        }
+        ctx.scheduled.set(true);
+
+        let delay = ctx.current_backoff_ms.get();
+        ctx.retries.set(ctx.retries.get() + 1);
+        let next = delay.saturating_mul(2).min(ctx.backoff_max_ms);
+        ctx.current_backoff_ms.set(next);
+
+        warn!("BRP WebSocket relay: reconnecting in {delay}ms");
+        let ctx = ctx.clone();
+        Timeout::new(delay, move || connect(ctx)).forget();
+    }
+
+    /// Process an incoming message from the relay.
+    ///
+    /// The payload is either a single JSON-RPC object or, per the 2.0 spec, a
+    /// batch — an array of request objects. Batches are dispatched through the
+    /// same BRP path and answered with a single array in request order.
+    async fn process_request(text: String, ctx: Rc<RelayContext>, ws: WebSocket) {
         // Parse JSON-RPC envelope
         let request: Value = match serde_json::from_str(&text) {
             Ok(v) => v,
@@ -193,6 +498,17 @@ mod wasm {
             }
         };
 
+        if let Value::Array(items) = request {
+            handle_batch(ctx, ws, items).await;
+            return;
+        }
+
+        dispatch_object(ctx, ws, request).await;
+    }
+
+    /// Handle a single JSON-RPC object, streaming watch responses over the
+    /// socket as they arrive.
+    async fn dispatch_object(ctx: Rc<RelayContext>, ws: WebSocket, request: Value) {
         let id = request.get("id").cloned();
         let method = match request.get("method").and_then(|m| m.as_str()) {
             Some(m) => m.to_string(),
@@ -203,28 +519,237 @@ mod wasm {
         };
         let params = request.get("params").cloned();
 
+        // Control methods for client-initiated teardown of a single watch.
+        if method == "brp/cancel" || method == "rpc.cancel" {
+            handle_cancel(&ctx, &ws, id, params);
+            return;
+        }
+
+        dispatch(ctx, ws, id, method, params).await;
+    }
+
+    /// Handle a `brp/cancel` / `rpc.cancel` control request.
+    ///
+    /// The `params` carry the `id` of the watch to stop, either as a bare value
+    /// or wrapped in an `{ "id": ... }` object. Signalling the cancel channel
+    /// drops the streaming loop's `result_receiver`, which in turn releases the
+    /// `result_sender` and lets the app-side streaming work wind down.
+    fn handle_cancel(ctx: &Rc<RelayContext>, ws: &WebSocket, id: Option<Value>, params: Option<Value>) {
+        let target = match params {
+            Some(Value::Object(map)) => map.get("id").cloned(),
+            Some(other) => Some(other),
+            None => None,
+        };
+        let Some(target) = target else {
+            send_error_response(ws, id.as_ref(), -32602, "cancel requires a target id");
+            return;
+        };
+
+        let key = watch_key(&target);
+        match ctx.cancels.borrow_mut().remove(&key) {
+            Some(cancel) => {
+                let _ = cancel.try_send(());
+                // A client-cancelled watch must not be re-issued on reconnect.
+                ctx.watches.borrow_mut().remove(&key);
+                let response = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "result": { "cancelled": target },
+                });
+                let _ = ws.send_with_str(&response.to_string());
+            }
+            None => {
+                send_error_response(ws, id.as_ref(), -32602, "no active subscription for that id");
+            }
+        }
+    }
+
+    /// Handle a JSON-RPC batch: dispatch every element concurrently and reply
+    /// with a single array in request order.
+    ///
+    /// Per the 2.0 spec an empty array is itself an invalid request, notification
+    /// elements (no `id`) contribute no response entry, and a batch of only
+    /// notifications is answered with silence. Watch methods are rejected since
+    /// their streaming responses cannot be framed inside a batch reply.
+    async fn handle_batch(ctx: Rc<RelayContext>, ws: WebSocket, items: Vec<Value>) {
+        if items.is_empty() {
+            send_error_response(&ws, None, -32600, "Invalid Request");
+            return;
+        }
+
+        let responses = join_all(items.into_iter().map(|item| {
+            let ctx = ctx.clone();
+            async move { batch_element(ctx, item).await }
+        }))
+        .await;
+
+        let responses: Vec<Value> = responses.into_iter().flatten().collect();
+
+        // A batch made up entirely of notifications gets no reply at all.
+        if let Some(reply) = batch_reply(responses) {
+            let _ = ws.send_with_str(&reply.to_string());
+        }
+    }
+
+    /// Shape the final batch reply from the per-element responses.
+    ///
+    /// Returns `None` when nothing should be sent — the case of a batch whose
+    /// elements were all notifications.
+    fn batch_reply(responses: Vec<Value>) -> Option<Value> {
+        if responses.is_empty() {
+            None
+        } else {
+            Some(Value::Array(responses))
+        }
+    }
+
+    /// The dispatchable classification of a single batch element.
+    enum BatchElement {
+        /// The element is invalid or a watch method: reply with this error.
+        Reject(Value),
+        /// A notification (no `id`): execute but produce no reply entry.
+        Notify { method: String, params: Option<Value> },
+        /// A regular call awaiting a response keyed by `id`.
+        Call {
+            id: Value,
+            method: String,
+            params: Option<Value>,
+        },
+    }
+
+    /// Validate and classify a batch element without performing any I/O.
+    ///
+    /// Malformed elements and watch methods are rejected (the latter before the
+    /// notification short-circuit, so a notification watch is not silently spun
+    /// up and torn down on the app side).
+    fn classify_element(item: Value) -> BatchElement {
+        if !item.is_object() {
+            return BatchElement::Reject(error_value(Value::Null, -32600, "Invalid Request"));
+        }
+
+        let id = item.get("id").cloned();
+        let method = match item.get("method").and_then(|m| m.as_str()) {
+            Some(m) => m.to_string(),
+            None => {
+                return BatchElement::Reject(error_value(
+                    id.unwrap_or(Value::Null),
+                    -32600,
+                    "Invalid Request",
+                ));
+            }
+        };
+        let params = item.get("params").cloned();
+
+        if method.contains("+watch") {
+            return BatchElement::Reject(error_value(
+                id.unwrap_or(Value::Null),
+                -32600,
+                "Watch methods are not supported in batch requests",
+            ));
+        }
+
+        match id {
+            Some(id) => BatchElement::Call { id, method, params },
+            None => BatchElement::Notify { method, params },
+        }
+    }
+
+    /// Dispatch one element of a batch, returning its response object, or `None`
+    /// for notifications (which execute but produce no reply entry).
+    async fn batch_element(ctx: Rc<RelayContext>, item: Value) -> Option<Value> {
+        match classify_element(item) {
+            BatchElement::Reject(err) => Some(err),
+            BatchElement::Notify { method, params } => {
+                let (result_sender, _result_receiver) = async_channel::bounded(1);
+                let _ = ctx
+                    .sender
+                    .send(BrpMessage {
+                        method,
+                        params,
+                        sender: result_sender,
+                    })
+                    .await;
+                None
+            }
+            BatchElement::Call { id, method, params } => {
+                let (result_sender, result_receiver) = async_channel::bounded(1);
+                if ctx
+                    .sender
+                    .send(BrpMessage {
+                        method,
+                        params,
+                        sender: result_sender,
+                    })
+                    .await
+                    .is_err()
+                {
+                    return Some(error_value(id, -32603, "BRP channel closed"));
+                }
+
+                match result_receiver.recv().await {
+                    Ok(result) => Some(make_response_value(Some(&id), result)),
+                    Err(_) => Some(error_value(id, -32603, "BRP channel closed")),
+                }
+            }
+        }
+    }
+
+    /// Forward a request to BRP and relay its response(s) back over the socket.
+    ///
+    /// Watch methods stream until the BRP side ends the stream, a socket send
+    /// fails, or the client cancels; their subscription is registered on
+    /// dispatch and removed only when the stream ends on its own, so the
+    /// reconnect subsystem can re-issue it on a new socket.
+    async fn dispatch(
+        ctx: Rc<RelayContext>,
+        ws: WebSocket,
+        id: Option<Value>,
+        method: String,
+        params: Option<Value>,
+    ) {
         // Create per-request response channel
         let is_watch = method.contains("+watch");
         let channel_size = if is_watch { 8 } else { 1 };
         let (result_sender, result_receiver) = async_channel::bounded(channel_size);
 
         let message = BrpMessage {
-            method,
-            params,
+            method: method.clone(),
+            params: params.clone(),
             sender: result_sender,
         };
 
-        if sender.send(message).await.is_err() {
+        if ctx.sender.send(message).await.is_err() {
             send_error_response(&ws, id.as_ref(), -32603, "BRP channel closed");
             return;
         }
 
-        // For watching requests, stream multiple responses
+        // For watching requests, stream multiple responses until the stream
+        // ends, the socket drops, or the client cancels the subscription.
         if is_watch {
-            while let Ok(result) = result_receiver.recv().await {
-                let response = make_response(id.as_ref(), result);
-                if ws.send_with_str(&response).is_err() {
-                    break;
+            let key = id.as_ref().map(watch_key);
+            let cancel_rx = key.as_ref().map(|key| {
+                ctx.watches.borrow_mut().insert(
+                    key.clone(),
+                    WatchEntry {
+                        id: id.clone().unwrap(),
+                        method,
+                        params,
+                    },
+                );
+                let (cancel_tx, cancel_rx) = async_channel::bounded(1);
+                ctx.cancels.borrow_mut().insert(key.clone(), cancel_tx);
+                cancel_rx
+            });
+
+            let ended = stream_watch(&ws, id.as_ref(), &result_receiver, cancel_rx.as_ref()).await;
+
+            if let Some(key) = key.as_ref() {
+                ctx.cancels.borrow_mut().remove(key);
+                // Only drop the re-issue registration when the stream ended on
+                // its own; a socket drop keeps it for reconnect re-issuance, and
+                // a client cancel already removed it in `handle_cancel`.
+                if ended {
+                    ctx.watches.borrow_mut().remove(key);
                 }
             }
         } else if let Ok(result) = result_receiver.recv().await {
@@ -233,8 +758,56 @@ mod wasm {
         }
     }
 
+    /// Stream watch frames over the socket until one of three things happens.
+    ///
+    /// Returns `true` when the stream ended on its own (the BRP side closed the
+    /// channel) and `false` when it was interrupted by a failed socket send or a
+    /// cancel signal — the distinction decides whether the re-issue registration
+    /// is dropped by the caller.
+    async fn stream_watch(
+        ws: &WebSocket,
+        id: Option<&Value>,
+        result_receiver: &async_channel::Receiver<Result<Value, BrpError>>,
+        cancel_rx: Option<&async_channel::Receiver<()>>,
+    ) -> bool {
+        loop {
+            let recv = result_receiver.recv();
+            // Without a registered cancel channel (watch carried no id) there is
+            // nothing to select against, so just await the next frame.
+            let Some(cancel_rx) = cancel_rx else {
+                match recv.await {
+                    Ok(result) => {
+                        let response = make_response(id, result);
+                        if ws.send_with_str(&response).is_err() {
+                            return false;
+                        }
+                    }
+                    Err(_) => return true,
+                }
+                continue;
+            };
+
+            let cancel = cancel_rx.recv();
+            pin_mut!(recv, cancel);
+            match select(recv, cancel).await {
+                Either::Left((Ok(result), _)) => {
+                    let response = make_response(id, result);
+                    if ws.send_with_str(&response).is_err() {
+                        return false;
+                    }
+                }
+                Either::Left((Err(_), _)) => return true,
+                Either::Right(_) => return false,
+            }
+        }
+    }
+
     fn make_response(id: Option<&Value>, result: Result<Value, BrpError>) -> String {
-        let response = match result {
+        make_response_value(id, result).to_string()
+    }
+
+    fn make_response_value(id: Option<&Value>, result: Result<Value, BrpError>) -> Value {
+        match result {
             Ok(value) => serde_json::json!({
                 "jsonrpc": "2.0",
                 "id": id,
@@ -249,19 +822,589 @@ mod wasm {
                     "error": error_value,
                 })
             }
-        };
-        response.to_string()
+        }
     }
 
-    fn send_error_response(ws: &WebSocket, id: Option<&Value>, code: i16, message: &str) {
-        let response = serde_json::json!({
+    fn error_value(id: Value, code: i16, message: &str) -> Value {
+        serde_json::json!({
             "jsonrpc": "2.0",
             "id": id,
             "error": {
                 "code": code,
                 "message": message,
             },
+        })
+    }
+
+    fn send_error_response(ws: &WebSocket, id: Option<&Value>, code: i16, message: &str) {
+        let _ = ws.send_with_str(&error_value(id.cloned().unwrap_or(Value::Null), code, message).to_string());
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+        use wasm_bindgen_test::wasm_bindgen_test;
+
+        fn code_of(value: &Value) -> i64 {
+            value["error"]["code"].as_i64().unwrap()
+        }
+
+        #[wasm_bindgen_test]
+        fn watch_key_is_canonical_json() {
+            assert_eq!(watch_key(&json!(7)), "7");
+            assert_eq!(watch_key(&json!("abc")), "\"abc\"");
+        }
+
+        #[wasm_bindgen_test]
+        fn append_query_picks_separator() {
+            let params = vec![("token".to_string(), "secret".to_string())];
+            assert_eq!(
+                append_query("ws://h/brp-relay".to_string(), &params),
+                "ws://h/brp-relay?token=secret"
+            );
+            assert_eq!(
+                append_query("ws://h/brp-relay?x=1".to_string(), &params),
+                "ws://h/brp-relay?x=1&token=secret"
+            );
+        }
+
+        #[wasm_bindgen_test]
+        fn empty_batch_is_invalid_request() {
+            // The payload `handle_batch` sends for an empty array.
+            assert_eq!(code_of(&error_value(Value::Null, -32600, "Invalid Request")), -32600);
+        }
+
+        #[wasm_bindgen_test]
+        fn all_notifications_yield_no_reply() {
+            assert!(batch_reply(vec![]).is_none());
+            assert!(batch_reply(vec![json!({"id": 1})]).is_some());
+        }
+
+        #[wasm_bindgen_test]
+        fn watch_in_batch_is_rejected() {
+            // Both an id-bearing and a notification watch must be rejected.
+            let with_id = classify_element(json!({"id": 1, "method": "bevy/get+watch"}));
+            assert!(matches!(with_id, BatchElement::Reject(err) if code_of(&err) == -32600));
+
+            let notification = classify_element(json!({"method": "bevy/get+watch"}));
+            assert!(matches!(notification, BatchElement::Reject(err) if code_of(&err) == -32600));
+        }
+
+        #[wasm_bindgen_test]
+        fn classify_distinguishes_calls_and_notifications() {
+            assert!(matches!(
+                classify_element(json!({"id": 1, "method": "bevy/list"})),
+                BatchElement::Call { .. }
+            ));
+            assert!(matches!(
+                classify_element(json!({"method": "bevy/list"})),
+                BatchElement::Notify { .. }
+            ));
+            assert!(matches!(
+                classify_element(json!([1, 2, 3])),
+                BatchElement::Reject(_)
+            ));
+        }
+    }
+}
+
+/// Default TCP port the native relay server listens on for HTTP BRP requests.
+pub const DEFAULT_RELAY_PORT: u16 = 15702;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod server {
+    use bevy_app::prelude::*;
+    use bevy_log::prelude::*;
+    use bytes::Bytes;
+    use futures_util::{SinkExt, StreamExt};
+    use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+    use hyper::body::{Frame, Incoming};
+    use hyper::header::{
+        CONNECTION, CONTENT_TYPE, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY,
+        SEC_WEBSOCKET_PROTOCOL, UPGRADE,
+    };
+    use hyper::service::service_fn;
+    use hyper::{Request, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message, Role};
+    use tokio_tungstenite::WebSocketStream;
+
+    type ResponseBody = BoxBody<Bytes, Infallible>;
+
+    /// Plugin that runs the native half of the relay: an HTTP JSON-RPC endpoint
+    /// for standard BRP tooling that bridges requests over a WebSocket to the
+    /// Bevy app running in a browser (see [`BrpWebSocketRelayPlugin`]).
+    ///
+    /// Enabled only on non-WASM targets. The server runs on its own Tokio
+    /// runtime in a background thread so it does not block the Bevy schedule.
+    ///
+    /// [`BrpWebSocketRelayPlugin`]: super::BrpWebSocketRelayPlugin
+    pub struct BrpRelayServerPlugin {
+        /// TCP port to serve HTTP BRP and accept browser WebSocket upgrades on.
+        pub port: u16,
+        /// WebSocket path browsers connect to (without a leading slash).
+        pub path: String,
+        /// Token a browser must present to be accepted.
+        ///
+        /// When `Some`, an upgrading browser must offer a matching token as its
+        /// WebSocket subprotocol (or `?token=` query param); otherwise the
+        /// connection is closed with [`AUTH_REJECTED_CLOSE_CODE`]. When `None`,
+        /// any connection is accepted.
+        ///
+        /// [`AUTH_REJECTED_CLOSE_CODE`]: super::AUTH_REJECTED_CLOSE_CODE
+        pub auth_token: Option<String>,
+    }
+
+    impl Default for BrpRelayServerPlugin {
+        fn default() -> Self {
+            Self {
+                port: super::DEFAULT_RELAY_PORT,
+                path: super::DEFAULT_RELAY_PATH.to_string(),
+                auth_token: None,
+            }
+        }
+    }
+
+    impl Plugin for BrpRelayServerPlugin {
+        fn build(&self, _app: &mut App) {
+            let addr = SocketAddr::from(([0, 0, 0, 0], self.port));
+            let path = format!("/{}", self.path);
+            let auth_token = self.auth_token.clone();
+
+            std::thread::Builder::new()
+                .name("brp-relay-server".to_string())
+                .spawn(move || {
+                    let rt = tokio::runtime::Builder::new_multi_thread()
+                        .enable_all()
+                        .build()
+                        .expect("failed to build relay server runtime");
+                    rt.block_on(async move {
+                        if let Err(e) = run(addr, path, auth_token).await {
+                            error!("BRP relay server stopped: {e}");
+                        }
+                    });
+                })
+                .expect("failed to spawn relay server thread");
+        }
+    }
+
+    /// A pending HTTP request awaiting its correlated response from the browser.
+    ///
+    /// Carries the originating client's `id` so the relayed response can be
+    /// rewritten to the value the client sent, independent of the server-assigned
+    /// correlation id.
+    enum Pending {
+        /// A one-shot request, fulfilled by the first matching response.
+        Once {
+            client_id: Value,
+            tx: oneshot::Sender<Value>,
+        },
+        /// A `+watch` subscription whose frames are streamed to the HTTP body.
+        Watch {
+            client_id: Value,
+            tx: mpsc::UnboundedSender<Value>,
+        },
+    }
+
+    /// Shared relay state, mirroring the pending-request map ethers-providers
+    /// uses for its WebSocket transport.
+    #[derive(Clone, Default)]
+    struct RelayServer {
+        /// Monotonic source of server-assigned correlation ids.
+        next_id: Arc<AtomicU64>,
+        /// Correlation id -> pending HTTP request awaiting a response.
+        pending: Arc<Mutex<BTreeMap<u64, Pending>>>,
+        /// Sink for frames destined to the currently-connected browser socket.
+        outbound: Arc<Mutex<Option<mpsc::UnboundedSender<Message>>>>,
+        /// Token browsers must present, or `None` to accept any connection.
+        auth_token: Arc<Option<String>>,
+    }
+
+    async fn run(addr: SocketAddr, path: String, auth_token: Option<String>) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("BRP relay server: listening on http://{addr} (browser path {path})");
+
+        let state = RelayServer {
+            auth_token: Arc::new(auth_token),
+            ..Default::default()
+        };
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let io = TokioIo::new(stream);
+            let state = state.clone();
+            let path = path.clone();
+
+            tokio::spawn(async move {
+                let service = service_fn(move |req| handle(req, state.clone(), path.clone()));
+                if let Err(e) = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(io, service)
+                    .with_upgrades()
+                    .await
+                {
+                    warn!("BRP relay server: connection error: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle(
+        req: Request<Incoming>,
+        state: RelayServer,
+        path: String,
+    ) -> Result<Response<ResponseBody>, Infallible> {
+        if is_websocket_upgrade(&req) && req.uri().path() == path {
+            return Ok(accept_browser(req, state));
+        }
+        Ok(handle_http(req, state).await)
+    }
+
+    fn is_websocket_upgrade(req: &Request<Incoming>) -> bool {
+        header_contains(req, CONNECTION, "upgrade")
+            && header_contains(req, UPGRADE, "websocket")
+            && req.headers().contains_key(SEC_WEBSOCKET_KEY)
+    }
+
+    fn header_contains(req: &Request<Incoming>, name: hyper::header::HeaderName, needle: &str) -> bool {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_ascii_lowercase().contains(needle))
+            .unwrap_or(false)
+    }
+
+    /// Complete the WebSocket handshake and drive the browser connection.
+    ///
+    /// When a token is configured, an unauthorized browser still completes the
+    /// handshake but is immediately closed with [`AUTH_REJECTED_CLOSE_CODE`] so
+    /// the client can distinguish rejection from an ordinary drop.
+    ///
+    /// [`AUTH_REJECTED_CLOSE_CODE`]: super::AUTH_REJECTED_CLOSE_CODE
+    fn accept_browser(mut req: Request<Incoming>, state: RelayServer) -> Response<ResponseBody> {
+        let key = req
+            .headers()
+            .get(SEC_WEBSOCKET_KEY)
+            .map(|k| derive_accept_key(k.as_bytes()));
+
+        let subprotocol = subprotocol_token(req.headers());
+        let presented = presented_token(req.headers(), req.uri().query());
+        let authorized = match state.auth_token.as_ref() {
+            Some(expected) => presented.as_deref() == Some(expected.as_str()),
+            None => true,
+        };
+
+        tokio::spawn(async move {
+            match hyper::upgrade::on(&mut req).await {
+                Ok(upgraded) => {
+                    let mut ws = WebSocketStream::from_raw_socket(
+                        TokioIo::new(upgraded),
+                        Role::Server,
+                        None,
+                    )
+                    .await;
+                    if authorized {
+                        serve_browser(ws, state).await;
+                    } else {
+                        warn!("BRP relay server: rejecting unauthorized browser connection");
+                        let _ = ws
+                            .close(Some(CloseFrame {
+                                code: CloseCode::Policy,
+                                reason: "unauthorized".into(),
+                            }))
+                            .await;
+                    }
+                }
+                Err(e) => error!("BRP relay server: upgrade failed: {e}"),
+            }
         });
-        let _ = ws.send_with_str(&response.to_string());
+
+        let mut response = Response::new(empty_body());
+        *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        response
+            .headers_mut()
+            .insert(CONNECTION, "upgrade".parse().unwrap());
+        response
+            .headers_mut()
+            .insert(UPGRADE, "websocket".parse().unwrap());
+        if let Some(accept) = key {
+            response
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_ACCEPT, accept.parse().unwrap());
+        }
+        // Only echo a subprotocol the browser actually offered: returning one
+        // it never sent makes it abort the handshake, which would break the
+        // `?token=` query-param path used by generic clients.
+        if let Some(token) = subprotocol {
+            if let Ok(value) = token.parse() {
+                response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+            }
+        }
+        response
+    }
+
+    /// The token a browser presents, preferring the subprotocol over the query
+    /// param when both are set.
+    fn presented_token(headers: &hyper::HeaderMap, query: Option<&str>) -> Option<String> {
+        subprotocol_token(headers).or_else(|| query_token(query))
+    }
+
+    /// The token offered as the WebSocket subprotocol, i.e. the first
+    /// comma-separated value of the `Sec-WebSocket-Protocol` header.
+    fn subprotocol_token(headers: &hyper::HeaderMap) -> Option<String> {
+        let proto = headers
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok())?;
+        let first = proto.split(',').next()?.trim();
+        (!first.is_empty()).then(|| first.to_string())
+    }
+
+    /// The token carried in the `?token=` query parameter, for generic clients
+    /// that cannot set a subprotocol.
+    fn query_token(query: Option<&str>) -> Option<String> {
+        query?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token=").map(|v| v.to_string()))
+    }
+
+    /// Pump frames to/from the connected browser, routing each incoming response
+    /// to the HTTP request awaiting it.
+    async fn serve_browser(
+        ws: WebSocketStream<TokioIo<hyper::upgrade::Upgraded>>,
+        state: RelayServer,
+    ) {
+        info!("BRP relay server: browser connected");
+        let (mut sink, mut stream) = ws.split();
+        let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+        *state.outbound.lock().unwrap() = Some(tx);
+
+        let writer = tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                if sink.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        while let Some(Ok(msg)) = stream.next().await {
+            if let Message::Text(text) = msg {
+                route_response(&state, &text);
+            }
+        }
+
+        info!("BRP relay server: browser disconnected");
+        *state.outbound.lock().unwrap() = None;
+        state.pending.lock().unwrap().clear();
+        writer.abort();
+    }
+
+    /// Correlate a browser response back to its waiting HTTP request by id.
+    fn route_response(state: &RelayServer, text: &str) {
+        let Ok(value) = serde_json::from_str::<Value>(text) else {
+            return;
+        };
+        let Some(id) = value.get("id").and_then(Value::as_u64) else {
+            return;
+        };
+
+        let mut pending = state.pending.lock().unwrap();
+        let mut dropped_watch = false;
+        match pending.get(&id) {
+            Some(Pending::Watch { client_id, tx }) => {
+                // A send error means the HTTP client's body stream was dropped.
+                if tx.send(with_client_id(&value, client_id)).is_err() {
+                    dropped_watch = true;
+                }
+                // Otherwise keep the subscription registered for further frames.
+            }
+            Some(Pending::Once { .. }) => {
+                if let Some(Pending::Once { client_id, tx }) = pending.remove(&id) {
+                    let _ = tx.send(with_client_id(&value, &client_id));
+                }
+            }
+            None => {}
+        }
+        if dropped_watch {
+            pending.remove(&id);
+            drop(pending);
+            // Tell the browser to stop streaming so chunk0-4's teardown fires
+            // end-to-end instead of leaking a watch per disconnected client.
+            cancel_watch(state, id);
+        }
+    }
+
+    /// Forward a `brp/cancel` frame for server id `id` to the connected browser.
+    fn cancel_watch(state: &RelayServer, id: u64) {
+        if let Some(outbound) = state.outbound.lock().unwrap().as_ref() {
+            let frame = json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "brp/cancel",
+                "params": { "id": id },
+            });
+            let _ = outbound.send(Message::Text(frame.to_string()));
+        }
+    }
+
+    /// Rewrite a response's `id` to the value the originating client sent.
+    fn with_client_id(response: &Value, client_id: &Value) -> Value {
+        let mut response = response.clone();
+        if let Some(obj) = response.as_object_mut() {
+            obj.insert("id".to_string(), client_id.clone());
+        }
+        response
+    }
+
+    async fn handle_http(req: Request<Incoming>, state: RelayServer) -> Response<ResponseBody> {
+        let body = match req.into_body().collect().await {
+            Ok(b) => b.to_bytes(),
+            Err(_) => return error_response(StatusCode::BAD_REQUEST, "failed to read body"),
+        };
+        let request: Value = match serde_json::from_slice(&body) {
+            Ok(v) => v,
+            Err(_) => return error_response(StatusCode::BAD_REQUEST, "invalid JSON"),
+        };
+
+        let client_id = request.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return error_response(StatusCode::BAD_REQUEST, "missing method");
+        };
+        let method = method.to_string();
+        let params = request.get("params").cloned();
+
+        let outbound = state.outbound.lock().unwrap().clone();
+        let Some(outbound) = outbound else {
+            return error_response(StatusCode::SERVICE_UNAVAILABLE, "no browser connected");
+        };
+
+        // Assign a server-side correlation id and forward the request.
+        let server_id = state.next_id.fetch_add(1, Ordering::Relaxed);
+        let frame = json!({
+            "jsonrpc": "2.0",
+            "id": server_id,
+            "method": method,
+            "params": params,
+        });
+
+        if method.contains("+watch") {
+            let (tx, rx) = mpsc::unbounded_channel::<Value>();
+            state
+                .pending
+                .lock()
+                .unwrap()
+                .insert(server_id, Pending::Watch { client_id, tx });
+            if outbound.send(Message::Text(frame.to_string())).is_err() {
+                state.pending.lock().unwrap().remove(&server_id);
+                return error_response(StatusCode::SERVICE_UNAVAILABLE, "browser gone");
+            }
+            // Stream each frame for this id as a newline-delimited JSON body.
+            let stream = UnboundedReceiverStream::new(rx).map(|v| {
+                let mut line = v.to_string();
+                line.push('\n');
+                Ok::<_, Infallible>(Frame::data(Bytes::from(line)))
+            });
+            let body = StreamBody::new(stream).boxed();
+            return json_response(StatusCode::OK, body);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        state
+            .pending
+            .lock()
+            .unwrap()
+            .insert(server_id, Pending::Once { client_id, tx });
+        if outbound.send(Message::Text(frame.to_string())).is_err() {
+            state.pending.lock().unwrap().remove(&server_id);
+            return error_response(StatusCode::SERVICE_UNAVAILABLE, "browser gone");
+        }
+
+        match rx.await {
+            Ok(value) => json_response(StatusCode::OK, full_body(value.to_string())),
+            Err(_) => error_response(StatusCode::BAD_GATEWAY, "relay closed before response"),
+        }
+    }
+
+    fn json_response(status: StatusCode, body: ResponseBody) -> Response<ResponseBody> {
+        let mut response = Response::new(body);
+        *response.status_mut() = status;
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+        response
+    }
+
+    fn error_response(status: StatusCode, message: &str) -> Response<ResponseBody> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": Value::Null,
+            "error": { "code": -32603, "message": message },
+        });
+        json_response(status, full_body(body.to_string()))
+    }
+
+    fn full_body(text: String) -> ResponseBody {
+        Full::new(Bytes::from(text)).boxed()
+    }
+
+    fn empty_body() -> ResponseBody {
+        Full::new(Bytes::new()).boxed()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use hyper::header::HeaderMap;
+
+        fn headers_with_protocol(value: &str) -> HeaderMap {
+            let mut headers = HeaderMap::new();
+            headers.insert(SEC_WEBSOCKET_PROTOCOL, value.parse().unwrap());
+            headers
+        }
+
+        #[test]
+        fn subprotocol_takes_first_value() {
+            let headers = headers_with_protocol("secret, other");
+            assert_eq!(subprotocol_token(&headers).as_deref(), Some("secret"));
+        }
+
+        #[test]
+        fn query_token_reads_token_param() {
+            assert_eq!(query_token(Some("a=1&token=secret&b=2")).as_deref(), Some("secret"));
+            assert_eq!(query_token(Some("a=1")), None);
+            assert_eq!(query_token(None), None);
+        }
+
+        #[test]
+        fn presented_prefers_subprotocol_over_query() {
+            let headers = headers_with_protocol("from-header");
+            assert_eq!(
+                presented_token(&headers, Some("token=from-query")).as_deref(),
+                Some("from-header")
+            );
+            // With no subprotocol, the query param is used.
+            assert_eq!(
+                presented_token(&HeaderMap::new(), Some("token=from-query")).as_deref(),
+                Some("from-query")
+            );
+        }
+
+        #[test]
+        fn with_client_id_rewrites_id() {
+            let response = serde_json::json!({"jsonrpc": "2.0", "id": 99, "result": 1});
+            let rewritten = with_client_id(&response, &serde_json::json!("client-7"));
+            assert_eq!(rewritten["id"], serde_json::json!("client-7"));
+            assert_eq!(rewritten["result"], serde_json::json!(1));
+        }
     }
 }